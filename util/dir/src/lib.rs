@@ -19,13 +19,15 @@
 //! Dir utilities for platform-specific operations
 extern crate app_dirs;
 extern crate ethcore_bigint as bigint;
+extern crate journaldb;
 
 pub mod helpers;
 use std::{env, fs};
 use std::path::{PathBuf, Path};
 use bigint::hash::{H64, H256};
 use helpers::{replace_home, replace_home_and_local};
-use app_dirs::{AppInfo, get_app_root, AppDataType};
+use app_dirs::{AppInfo, get_app_root, data_root, AppDataType};
+use journaldb::Algorithm;
 // re-export platform-specific functions
 use platform::*;
 
@@ -42,6 +44,8 @@ use platform::*;
 // this const is irrelevent cause we do have migrations now,
 // but we still use it for backwards compatibility
 const LEGACY_CLIENT_DB_VER_STR: &'static str = "5.3";
+/// Current client database version
+const CLIENT_DB_VER_STR: &'static str = "6";
 
 #[derive(Debug, PartialEq)]
 /// Parity local data directories
@@ -93,6 +97,8 @@ impl Directories {
 		}
 		if secretstore_enabled {
 			fs::create_dir_all(&self.secretstore).map_err(|e| e.to_string())?;
+			fs::create_dir_all(&self.secretstore_keys_path()).map_err(|e| e.to_string())?;
+			fs::create_dir_all(&self.secretstore_self_key_path()).map_err(|e| e.to_string())?;
 		}
 		Ok(())
 	}
@@ -122,19 +128,116 @@ impl Directories {
 		dir.push(spec_name);
 		dir
 	}
+
+	/// Resolve a keystore location string (`"geth"`, `"geth-test"`, `"parity-<chain>"`, or a
+	/// literal path; an empty chain name resolves to the base keys directory)
+	pub fn resolve_keystore(&self, location: &str) -> PathBuf {
+		match location {
+			"geth" => geth(false),
+			"geth-test" => geth(true),
+			loc if loc.starts_with("parity-") => self.keys_path(&loc["parity-".len()..]),
+			loc => PathBuf::from(loc),
+		}
+	}
+
+	/// Get a path under the secretstore directory
+	pub fn secretstore_path(&self, sub: &str) -> PathBuf {
+		let mut dir = PathBuf::from(&self.secretstore);
+		dir.push(sub);
+		dir
+	}
+
+	/// Get the secretstore server key shares and document key files path
+	pub fn secretstore_keys_path(&self) -> PathBuf {
+		self.secretstore_path("keys")
+	}
+
+	/// Get the secretstore node's own key path
+	pub fn secretstore_self_key_path(&self) -> PathBuf {
+		self.secretstore_path("self")
+	}
+}
+
+#[derive(Debug, PartialEq, Clone)]
+/// Database directories for a given network
+pub struct DatabaseDirectories {
+	/// Base path
+	pub path: String,
+	/// Legacy path, used for locating and migrating a pre-versioning database.
+	pub legacy_path: String,
+	/// Genesis hash of the chain this database is for.
+	pub genesis_hash: H256,
+	/// Name of current fork, if any.
+	pub fork_name: Option<String>,
+}
+
+impl DatabaseDirectories {
+	/// Network directory name, keyed by genesis hash and fork name
+	fn network_path(&self) -> String {
+		let mut dir_name = H64::from(self.genesis_hash).hex();
+		if let Some(ref fork_name) = self.fork_name {
+			dir_name.push_str("-");
+			dir_name.push_str(fork_name);
+		}
+		dir_name
+	}
+
+	/// Get the root path for database
+	pub fn version_path(&self, pruning: Algorithm) -> PathBuf {
+		let mut dir = Path::new(&self.path).to_path_buf();
+		dir.push(CLIENT_DB_VER_STR);
+		dir.push(self.network_path());
+		dir.push(pruning.as_internal_name_str());
+		dir
+	}
+
+	/// Get the path for the database itself
+	pub fn db_path(&self, pruning: Algorithm) -> PathBuf {
+		let mut dir = self.version_path(pruning);
+		dir.push("db");
+		dir
+	}
+
+	/// Get the legacy-style (pre-versioning) path for the database
+	pub fn legacy_version_path(&self) -> PathBuf {
+		let mut dir = Path::new(&self.legacy_path).to_path_buf();
+		dir.push(LEGACY_CLIENT_DB_VER_STR);
+		dir.push(self.network_path());
+		dir
+	}
+}
+
+
+/// Resolve a data directory, preferring an existing legacy location over the standardized one
+fn default_path(t: AppDataType) -> Option<PathBuf> {
+	let app_info = AppInfo { name: PRODUCT, author: AUTHOR };
+	if let Ok(legacy) = get_app_root(t, &app_info) {
+		if legacy.exists() {
+			return Some(legacy);
+		}
+	}
+	data_root(t).ok().map(|mut path| {
+		if !LOWERCASE {
+			path.push(AUTHOR);
+		}
+		path.push(if LOWERCASE { PRODUCT.to_lowercase() } else { PRODUCT.to_owned() });
+		path
+	})
 }
 
+/// Default data path as a `PathBuf`
+pub fn default_data_pathbuf() -> PathBuf {
+	default_path(AppDataType::UserData).unwrap_or_else(|| PathBuf::from("$HOME/.parity"))
+}
 
 /// Default data path
 pub fn default_data_path() -> String {
-	let app_info = AppInfo { name: PRODUCT, author: AUTHOR };
-	get_app_root(AppDataType::UserData, &app_info).map(|p| p.to_string_lossy().into_owned()).unwrap_or_else(|_| "$HOME/.parity".to_owned())
+	default_data_pathbuf().to_string_lossy().into_owned()
 }
 
 /// Default local path
 pub fn default_local_path() -> String {
-	let app_info = AppInfo { name: PRODUCT, author: AUTHOR };
-	get_app_root(AppDataType::UserCache, &app_info).map(|p| p.to_string_lossy().into_owned()).unwrap_or_else(|_| "$HOME/.parity".to_owned())
+	default_path(AppDataType::UserCache).unwrap_or_else(|| PathBuf::from("$HOME/.parity")).to_string_lossy().into_owned()
 }
 
 /// Default hypervisor path
@@ -143,9 +246,32 @@ pub fn default_hypervisor_path() -> String {
 	get_app_root(AppDataType::UserData, &app_info).map(|p| p.to_string_lossy().into_owned()).unwrap_or_else(|_| "$HOME/.parity-hypervisor".to_owned())
 }
 
-/// Get home directory.
-fn home() -> PathBuf {
-	env::home_dir().expect("Failed to get home dir")
+/// Environment variable overriding the resolved home/base directory
+const BASE_DIR_OVERRIDE_ENV: &'static str = "PARITY_BASE_DIR";
+
+/// Get home directory
+fn home() -> Option<PathBuf> {
+	env::var(BASE_DIR_OVERRIDE_ENV).ok().map(PathBuf::from).or_else(robust_home_dir)
+}
+
+/// Home directory lookup that doesn't rely on `env::home_dir`'s buggy Windows behaviour
+#[cfg(windows)]
+fn robust_home_dir() -> Option<PathBuf> {
+	if let Ok(profile) = env::var("USERPROFILE") {
+		if !profile.is_empty() {
+			return Some(PathBuf::from(profile));
+		}
+	}
+	if let (Ok(drive), Ok(path)) = (env::var("HOMEDRIVE"), env::var("HOMEPATH")) {
+		return Some(PathBuf::from(format!("{}{}", drive, path)));
+	}
+	env::home_dir()
+}
+
+/// Home directory lookup that doesn't rely on `env::home_dir`'s buggy Windows behaviour
+#[cfg(not(windows))]
+fn robust_home_dir() -> Option<PathBuf> {
+	env::home_dir()
 }
 
 /// Geth path
@@ -165,15 +291,21 @@ pub fn parity(chain: &str) -> PathBuf {
 	base
 }
 
+/// Resolve a keystore location string against the default data directories.
+pub fn resolve_keystore_location(location: &str) -> PathBuf {
+	Directories::default().resolve_keystore(location)
+}
+
 #[cfg(target_os = "macos")]
 mod platform {
 	use std::path::PathBuf;
 	pub const AUTHOR: &'static str = "Parity";
 	pub const PRODUCT: &'static str = "io.parity.ethereum";
 	pub const PRODUCT_HYPERVISOR: &'static str = "io.parity.ethereum-updates";
+	pub const LOWERCASE: bool = true;
 
 	pub fn parity_base() -> PathBuf {
-		let mut home = super::home();
+		let mut home = super::home().unwrap_or_else(|| PathBuf::from("."));
 		home.push("Library");
 		home.push("Application Support");
 		home.push("io.parity.ethereum");
@@ -182,7 +314,7 @@ mod platform {
 	}
 
 	pub fn geth_base() -> PathBuf {
-		let mut home = super::home();
+		let mut home = super::home().unwrap_or_else(|| PathBuf::from("."));
 		home.push("Library");
 		home.push("Ethereum");
 		home
@@ -195,9 +327,10 @@ mod platform {
 	pub const AUTHOR: &'static str = "Parity";
 	pub const PRODUCT: &'static str = "Ethereum";
 	pub const PRODUCT_HYPERVISOR: &'static str = "EthereumUpdates";
+	pub const LOWERCASE: bool = false;
 
 	pub fn parity_base() -> PathBuf {
-		let mut home = super::home();
+		let mut home = super::home().unwrap_or_else(|| PathBuf::from("."));
 		home.push("AppData");
 		home.push("Roaming");
 		home.push("Parity");
@@ -207,7 +340,7 @@ mod platform {
 	}
 
 	pub fn geth_base() -> PathBuf {
-		let mut home = super::home();
+		let mut home = super::home().unwrap_or_else(|| PathBuf::from("."));
 		home.push("AppData");
 		home.push("Roaming");
 		home.push("Ethereum");
@@ -221,9 +354,10 @@ mod platform {
 	pub const AUTHOR: &'static str = "parity";
 	pub const PRODUCT: &'static str = "io.parity.ethereum";
 	pub const PRODUCT_HYPERVISOR: &'static str = "io.parity.ethereum-updates";
+	pub const LOWERCASE: bool = true;
 
 	pub fn parity_base() -> PathBuf {
-		let mut home = super::home();
+		let mut home = super::home().unwrap_or_else(|| PathBuf::from("."));
 		home.push(".local");
 		home.push("share");
 		home.push("io.parity.ethereum");
@@ -232,7 +366,7 @@ mod platform {
 	}
 
 	pub fn geth_base() -> PathBuf {
-		let mut home = super::home();
+		let mut home = super::home().unwrap_or_else(|| PathBuf::from("."));
 		home.push(".ethereum");
 		home
 	}
@@ -240,11 +374,18 @@ mod platform {
 
 #[cfg(test)]
 mod tests {
+	use std::sync::Mutex;
 	use super::Directories;
 	use helpers::{replace_home, replace_home_and_local};
 
+	// Guards tests that read or mutate process-global state (the real app-root directory,
+	// `$HOME`, `PARITY_BASE_DIR`) so they don't race each other under the default parallel
+	// test runner.
+	static GLOBAL_STATE_MUTEX: Mutex<()> = Mutex::new(());
+
 	#[test]
 	fn test_default_directories() {
+		let _guard = GLOBAL_STATE_MUTEX.lock().unwrap();
 		let data_dir = super::default_data_path();
 		let local_dir = super::default_local_path();
 		let expected = Directories {
@@ -264,4 +405,95 @@ mod tests {
 		};
 		assert_eq!(expected, Directories::default());
 	}
+
+	#[test]
+	fn test_default_path_prefers_existing_legacy_dir() {
+		use std::fs;
+		use app_dirs::{AppInfo, AppDataType, get_app_root};
+
+		let _guard = GLOBAL_STATE_MUTEX.lock().unwrap();
+		let legacy = get_app_root(AppDataType::UserData, &AppInfo { name: super::PRODUCT, author: super::AUTHOR })
+			.expect("app root should resolve in a test environment");
+		fs::create_dir_all(&legacy).expect("create legacy dir");
+		assert_eq!(super::default_path(AppDataType::UserData), Some(legacy.clone()));
+		fs::remove_dir_all(&legacy).ok();
+	}
+
+	#[test]
+	fn test_default_path_falls_back_when_no_legacy_dir() {
+		use std::fs;
+		use app_dirs::{AppInfo, AppDataType, get_app_root, data_root};
+
+		let _guard = GLOBAL_STATE_MUTEX.lock().unwrap();
+		let legacy = get_app_root(AppDataType::UserData, &AppInfo { name: super::PRODUCT, author: super::AUTHOR })
+			.expect("app root should resolve in a test environment");
+		fs::remove_dir_all(&legacy).ok();
+		assert!(!legacy.exists());
+
+		let mut expected = data_root(AppDataType::UserData).expect("data root should resolve in a test environment");
+		if !super::LOWERCASE {
+			expected.push(super::AUTHOR);
+		}
+		expected.push(if super::LOWERCASE { super::PRODUCT.to_lowercase() } else { super::PRODUCT.to_owned() });
+
+		assert_eq!(super::default_path(AppDataType::UserData), Some(expected));
+	}
+
+	#[test]
+	fn test_database_directories_paths() {
+		use super::DatabaseDirectories;
+		use bigint::hash::H256;
+		use journaldb::Algorithm;
+
+		let directories = DatabaseDirectories {
+			path: "/home/parity/db".into(),
+			legacy_path: "/home/parity/legacy".into(),
+			genesis_hash: H256::from(0x1234567890abcdefu64),
+			fork_name: Some("morden".into()),
+		};
+
+		let version_path = directories.version_path(Algorithm::Archive);
+		assert_eq!(version_path.file_name().unwrap(), "archive");
+		assert!(directories.db_path(Algorithm::Archive).ends_with("db"));
+		assert!(directories.legacy_version_path().to_str().unwrap().contains("morden"));
+
+		let other_hash = DatabaseDirectories { genesis_hash: H256::from(0xfedcba0987654321u64), ..directories.clone() };
+		assert!(other_hash.version_path(Algorithm::Archive) != directories.version_path(Algorithm::Archive));
+		assert!(other_hash.legacy_version_path() != directories.legacy_version_path());
+
+		let no_fork = DatabaseDirectories { fork_name: None, ..directories.clone() };
+		assert!(no_fork.version_path(Algorithm::Archive) != directories.version_path(Algorithm::Archive));
+	}
+
+	#[test]
+	fn test_resolve_keystore() {
+		use std::path::PathBuf;
+
+		let _guard = GLOBAL_STATE_MUTEX.lock().unwrap();
+		let dirs = Directories::default();
+		assert_eq!(dirs.resolve_keystore("geth"), super::geth(false));
+		assert_eq!(dirs.resolve_keystore("geth-test"), super::geth(true));
+		assert_eq!(dirs.resolve_keystore("parity-morden"), dirs.keys_path("morden"));
+		assert_eq!(dirs.resolve_keystore("/custom/path"), PathBuf::from("/custom/path"));
+	}
+
+	#[test]
+	fn test_home_respects_override_env_var() {
+		use std::env;
+		use std::path::PathBuf;
+
+		let _guard = GLOBAL_STATE_MUTEX.lock().unwrap();
+		env::set_var(super::BASE_DIR_OVERRIDE_ENV, "/tmp/parity-test-home");
+		assert_eq!(super::home(), Some(PathBuf::from("/tmp/parity-test-home")));
+		env::remove_var(super::BASE_DIR_OVERRIDE_ENV);
+		assert_eq!(super::home(), super::robust_home_dir());
+	}
+
+	#[test]
+	fn test_secretstore_paths() {
+		let _guard = GLOBAL_STATE_MUTEX.lock().unwrap();
+		let dirs = Directories::default();
+		assert_eq!(dirs.secretstore_keys_path(), dirs.secretstore_path("keys"));
+		assert_eq!(dirs.secretstore_self_key_path(), dirs.secretstore_path("self"));
+	}
 }